@@ -100,6 +100,11 @@ struct SudoArgs {
     shell: ShellArgs,
 }
 
+/// Environment variables that `-E` never forwards, even
+/// though they're present in the shim's own environment, because handing
+/// them to the target command can change how it resolves code to run.
+const ENV_DENYLIST: &[&str] = &["PATH", "LD_PRELOAD", "LD_LIBRARY_PATH", "IFS"];
+
 macro_rules! exit {
     ($($tok:tt)*) => {{
         eprintln!($($tok)*);
@@ -108,9 +113,6 @@ macro_rules! exit {
 }
 
 fn main() {
-    let mut cmd = std::process::Command::new("run0");
-    cmd.arg("--background=");
-
     let mut raw_args = std::env::args_os().peekable();
     let arg0 = raw_args.next().unwrap_or_else(|| OsString::from("sudo"));
     let args = SudoArgs::parse_from(
@@ -125,9 +127,19 @@ fn main() {
         ),
     );
 
-    let Ok(command): Result<Vec<String>, _> = raw_args.map(|a| a.into_string()).collect() else {
-        exit!("failed to parse arguments as utf8");
-    };
+    let command: Vec<OsString> = raw_args.collect();
+
+    let mut cmd = build_command(args, command);
+
+    let err = cmd.exec();
+    exit!("failed to execute command: {err}")
+}
+
+/// Builds the `run0` invocation equivalent to the parsed sudo-style `args`
+/// and trailing `command`.
+fn build_command(args: SudoArgs, command: Vec<OsString>) -> std::process::Command {
+    let mut cmd = std::process::Command::new("run0");
+    cmd.arg("--background=");
 
     // Unsupported/validation
 
@@ -174,16 +186,12 @@ fn main() {
         exit!("cannot validate credentials")
     }
 
-    if args.preserve_all_env {
+    if args.preserve_all_env && std::env::var_os("SUDO_SHIM_STRICT").is_some() {
         exit!("you may not preserve the entire environment, you cretin!")
     }
 
     // Unimplemented
 
-    if args.background {
-        exit!("cannot run commands in the background")
-    }
-
     if args.remove_timestamp || args.reset_timestamp {
         exit!("cannot alter sudo timestamps")
     }
@@ -192,8 +200,8 @@ fn main() {
         exit!("chroot is unimplemented")
     }
 
-    if args.command_timeout.is_some() {
-        exit!("command timeouts are unimplemented")
+    if args.background {
+        exit!("cannot run commands in the background")
     }
 
     // Flags
@@ -202,17 +210,44 @@ fn main() {
         cmd.arg("-D").arg(dir);
     }
 
+    if let Some(timeout) = &args.command_timeout {
+        let secs = parse_timeout(timeout).unwrap_or_else(|e| exit!("{e}"));
+        if secs == 0 {
+            exit!("command timeout must be greater than zero, was {timeout:?}")
+        }
+        cmd.arg(format!("--property=RuntimeMaxSec={secs}"));
+    }
+
+    if args.preserve_all_env {
+        for (key, value) in std::env::vars_os() {
+            let Some(key) = key.to_str() else { continue };
+            if ENV_DENYLIST.contains(&key) {
+                continue;
+            }
+            let mut arg = OsString::from(format!("--setenv={key}="));
+            arg.push(&value);
+            cmd.arg(arg);
+        }
+    }
+
     for var in &args.preserve_env {
         cmd.arg(format!("--setenv={var}"));
     }
 
-    // XXX: parse GID/UID!
     if let Some(group) = &args.group {
-        cmd.arg("-g").arg(group);
+        let id = resolve_id(group, "group", Some('%'), |name| {
+            users::get_group_by_name(name).map(|g| g.gid())
+        })
+        .unwrap_or_else(|e| exit!("{e}"));
+        cmd.arg("-g").arg(id);
     }
 
     if let Some(user) = &args.user {
-        cmd.arg("-u").arg(user);
+        let id = resolve_id(user, "user", None, |name| {
+            users::get_user_by_name(name).map(|u| u.uid())
+        })
+        .unwrap_or_else(|e| exit!("{e}"));
+        cmd.arg("-u").arg(id);
     }
 
     if let Some(host) = &args.host {
@@ -258,22 +293,125 @@ fn main() {
         }
 
         if !command.is_empty() {
-            cmd.arg("-c").arg(shell_escape(&command));
+            let Ok(command): Result<Vec<String>, _> =
+                command.into_iter().map(OsString::into_string).collect()
+            else {
+                exit!("failed to parse arguments as utf8");
+            };
+            let mut script = shell_escape(&command);
+            if args.shell.login {
+                if let Some(dir) = &args.chdir {
+                    let Some(dir) = dir.to_str() else {
+                        exit!("--chdir with --login requires a UTF-8 path")
+                    };
+                    script = format!("cd -- {} && {script}", shell_escape_arg(dir));
+                }
+            }
+            cmd.arg("-c").arg(script);
+        } else if args.shell.login && args.chdir.is_some() {
+            exit!(
+                "--login with --chdir needs an explicit COMMAND, so the \
+                 requested directory can be reasserted after the login \
+                 shell's profile scripts run"
+            )
         }
     }
 
-    let err = cmd.exec();
-    exit!("failed to execute command: {err}")
+    cmd
+}
+
+/// Resolves a sudo-style `-u`/`-g` value (`NAME`, `#UID`/`#GID`, or, for
+/// groups, `%NAME`) to the numeric id `run0` expects.
+///
+/// `#` forces the remainder to be treated as a literal numeric id.
+/// Otherwise, `strip` (if given) strips a leading sigil such as `%` — this
+/// is only documented for `-g`, so `-u` passes `None` and treats a literal
+/// `%` as part of the username. The (possibly stripped) name is then looked
+/// up with `lookup`, falling back to a bare numeric string if the lookup
+/// fails.
+fn resolve_id(
+    spec: &str,
+    kind: &str,
+    strip: Option<char>,
+    lookup: impl FnOnce(&str) -> Option<u32>,
+) -> Result<String, String> {
+    let id = if let Some(literal) = spec.strip_prefix('#') {
+        literal
+            .parse::<u32>()
+            .map_err(|_| format!("invalid {kind} id: {spec}"))?
+    } else {
+        let name = strip.and_then(|c| spec.strip_prefix(c)).unwrap_or(spec);
+        match lookup(name) {
+            Some(id) => id,
+            None => name
+                .parse()
+                .map_err(|_| format!("unknown {kind}: {spec}"))?,
+        }
+    };
+    Ok(id.to_string())
+}
+
+/// Parses sudo's `-T`/`--command-timeout` grammar: either a bare integer
+/// number of seconds, or a concatenation of `<n>d`, `<n>h`, `<n>m`, `<n>s`
+/// segments (e.g. `1h30m`), and returns the total number of seconds.
+fn parse_timeout(spec: &str) -> Result<u64, String> {
+    if let Ok(secs) = spec.parse() {
+        return Ok(secs);
+    }
+
+    let invalid = || format!("invalid command timeout: {spec:?}");
+
+    if spec.is_empty() {
+        return Err(invalid());
+    }
+
+    let mut total = 0u64;
+    let mut digits = String::new();
+    for c in spec.chars() {
+        if c.is_ascii_digit() {
+            digits.push(c);
+            continue;
+        }
+        let unit = match c {
+            'd' => 24 * 60 * 60,
+            'h' => 60 * 60,
+            'm' => 60,
+            's' => 1,
+            _ => return Err(invalid()),
+        };
+        if digits.is_empty() {
+            return Err(invalid());
+        }
+        let n: u64 = digits.parse().map_err(|_| invalid())?;
+        total = n
+            .checked_mul(unit)
+            .and_then(|v| total.checked_add(v))
+            .ok_or_else(invalid)?;
+        digits.clear();
+    }
+    if !digits.is_empty() {
+        return Err(invalid());
+    }
+    Ok(total)
 }
 
+/// Quotes a single argument for a POSIX shell by wrapping it in single
+/// quotes, so that it is passed through verbatim with no expansion.
+///
+/// Embedded single quotes are rendered as `'\''` (close-quote, escaped-quote,
+/// reopen-quote), the standard POSIX-sh trick since single quotes cannot be
+/// escaped from within a single-quoted string.
 fn shell_escape_arg(s: &str) -> String {
-    let mut out = String::with_capacity(s.len());
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('\'');
     for c in s.chars() {
-        if !matches!(c, '_' | '-' | '$') && !c.is_ascii_alphanumeric() {
-            out.push('\\');
+        if c == '\'' {
+            out.push_str("'\\''");
+        } else {
+            out.push(c);
         }
-        out.push(c);
     }
+    out.push('\'');
     out
 }
 
@@ -283,3 +421,153 @@ fn shell_escape(cmd: impl IntoIterator<Item: AsRef<str>>) -> String {
         .collect::<Vec<_>>()
         .join(" ".as_ref())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_dollar() {
+        assert_eq!(shell_escape_arg("$(id)"), "'$(id)'");
+    }
+
+    #[test]
+    fn escapes_spaces() {
+        assert_eq!(shell_escape_arg("foo bar"), "'foo bar'");
+    }
+
+    #[test]
+    fn escapes_single_quotes() {
+        assert_eq!(shell_escape_arg("it's"), "'it'\\''s'");
+    }
+
+    #[test]
+    fn escapes_empty_string() {
+        assert_eq!(shell_escape_arg(""), "''");
+    }
+
+    #[test]
+    fn escapes_newlines() {
+        assert_eq!(shell_escape_arg("a\nb"), "'a\nb'");
+    }
+
+    #[test]
+    fn joins_with_spaces() {
+        assert_eq!(
+            shell_escape(["echo", "$(id)", "it's"]),
+            "'echo' '$(id)' 'it'\\''s'"
+        );
+    }
+
+    #[test]
+    fn resolve_id_literal_uid() {
+        assert_eq!(
+            resolve_id("#1000", "user", None, |_| None),
+            Ok("1000".to_string())
+        );
+    }
+
+    #[test]
+    fn resolve_id_invalid_literal_uid_errors() {
+        assert!(resolve_id("#nope", "user", None, |_| None).is_err());
+    }
+
+    #[test]
+    fn resolve_id_looks_up_name() {
+        assert_eq!(
+            resolve_id("alice", "user", None, |name| (name == "alice").then_some(1001)),
+            Ok("1001".to_string())
+        );
+    }
+
+    #[test]
+    fn resolve_id_strips_percent_for_groups() {
+        assert_eq!(
+            resolve_id("%wheel", "group", Some('%'), |name| (name == "wheel")
+                .then_some(10)),
+            Ok("10".to_string())
+        );
+    }
+
+    #[test]
+    fn resolve_id_keeps_percent_for_users() {
+        // `%` isn't a documented sigil for `-u`, so it stays part of the
+        // name and the lookup (and numeric fallback) both miss.
+        assert!(resolve_id("%wheel", "user", None, |_| None).is_err());
+    }
+
+    #[test]
+    fn resolve_id_falls_back_to_numeric_name() {
+        assert_eq!(
+            resolve_id("1000", "user", None, |_| None),
+            Ok("1000".to_string())
+        );
+    }
+
+    #[test]
+    fn resolve_id_unknown_name_errors() {
+        assert!(resolve_id("nobody", "user", None, |_| None).is_err());
+    }
+
+    #[test]
+    fn timeout_bare_seconds() {
+        assert_eq!(parse_timeout("90"), Ok(90));
+    }
+
+    #[test]
+    fn timeout_multi_segment() {
+        assert_eq!(parse_timeout("1h30m"), Ok(60 * 60 + 30 * 60));
+    }
+
+    #[test]
+    fn timeout_all_units() {
+        assert_eq!(
+            parse_timeout("1d2h3m4s"),
+            Ok(24 * 60 * 60 + 2 * 60 * 60 + 3 * 60 + 4)
+        );
+    }
+
+    #[test]
+    fn timeout_empty_string_is_invalid() {
+        assert!(parse_timeout("").is_err());
+    }
+
+    #[test]
+    fn timeout_dangling_unit_is_invalid() {
+        assert!(parse_timeout("1hh").is_err());
+    }
+
+    #[test]
+    fn timeout_trailing_digits_is_invalid() {
+        assert!(parse_timeout("1h30").is_err());
+    }
+
+    #[test]
+    fn timeout_overflow_is_rejected_not_panicking() {
+        assert!(parse_timeout("999999999999999d").is_err());
+    }
+
+    #[test]
+    fn login_with_chdir_reasserts_directory_after_profile() {
+        let args = SudoArgs::parse_from(["sudo", "-i", "-D", "/tmp/foo"]);
+        let cmd = build_command(args, vec![OsString::from("true")]);
+        let run0_args: Vec<String> = cmd
+            .get_args()
+            .map(|a| a.to_string_lossy().into_owned())
+            .collect();
+
+        assert!(run0_args.iter().any(|a| a == "--login"));
+        assert!(
+            run0_args
+                .windows(2)
+                .any(|w| w[0] == "-D" && w[1] == "/tmp/foo"),
+            "missing -D /tmp/foo in {run0_args:?}"
+        );
+        assert!(
+            run0_args
+                .iter()
+                .any(|a| a.starts_with("cd -- '/tmp/foo' && ")),
+            "missing chdir reassertion in {run0_args:?}"
+        );
+    }
+}